@@ -0,0 +1,99 @@
+//! A small Aho-Corasick multi-pattern matcher.
+//!
+//! Builds a trie of the given patterns, then links it into a deterministic
+//! automaton (goto/fail/output functions) so that an arbitrarily long
+//! haystack can be scanned for every occurrence of every pattern in a
+//! single O(n + matches) pass, instead of one O(n·m) scan per pattern.
+
+use std::collections::VecDeque;
+
+const ALPHABET_SIZE: usize = 256;
+
+struct Node {
+    /// Fully resolved transition table: after construction every entry is a
+    /// valid state index (no fail-link chasing needed at match time).
+    goto: [i32; ALPHABET_SIZE],
+    fail: usize,
+    /// Indices (into the original `patterns` slice) of every pattern that
+    /// ends at this state, including those inherited through fail links.
+    output: Vec<usize>,
+}
+
+pub struct AhoCorasick {
+    nodes: Vec<Node>,
+    pattern_lens: Vec<usize>,
+}
+
+impl AhoCorasick {
+    /// Builds the automaton from `patterns`. Patterns may repeat or share
+    /// prefixes; each is tracked by its index in `patterns`.
+    pub fn new(patterns: &[&[u8]]) -> Self {
+        let root = Node { goto: [-1; ALPHABET_SIZE], fail: 0, output: Vec::new() };
+        let mut nodes = vec![root];
+
+        // Build the trie.
+        for (pattern_idx, pattern) in patterns.iter().enumerate() {
+            let mut state = 0usize;
+            for &byte in pattern.iter() {
+                state = match nodes[state].goto[byte as usize] {
+                    existing if existing >= 0 => existing as usize,
+                    _ => {
+                        nodes.push(Node { goto: [-1; ALPHABET_SIZE], fail: 0, output: Vec::new() });
+                        let child = nodes.len() - 1;
+                        nodes[state].goto[byte as usize] = child as i32;
+                        child
+                    }
+                };
+            }
+            nodes[state].output.push(pattern_idx);
+        }
+
+        // Breadth-first pass computing fail links and completing goto into a
+        // full DFA (missing transitions fall back through the fail link).
+        let mut queue = VecDeque::new();
+        for byte in 0..ALPHABET_SIZE {
+            match nodes[0].goto[byte] {
+                -1 => nodes[0].goto[byte] = 0,
+                child => {
+                    nodes[child as usize].fail = 0;
+                    queue.push_back(child as usize);
+                }
+            }
+        }
+
+        while let Some(state) = queue.pop_front() {
+            let fail_output = nodes[nodes[state].fail].output.clone();
+            nodes[state].output.extend(fail_output);
+
+            for byte in 0..ALPHABET_SIZE {
+                match nodes[state].goto[byte] {
+                    -1 => {
+                        let fallback = nodes[nodes[state].fail].goto[byte];
+                        nodes[state].goto[byte] = fallback;
+                    }
+                    child => {
+                        nodes[child as usize].fail = nodes[nodes[state].fail].goto[byte] as usize;
+                        queue.push_back(child as usize);
+                    }
+                }
+            }
+        }
+
+        let pattern_lens = patterns.iter().map(|p| p.len()).collect();
+        AhoCorasick { nodes, pattern_lens }
+    }
+
+    /// Streams `haystack` through the automaton, yielding `(pattern_index,
+    /// match_start)` for every occurrence of every pattern, in the order the
+    /// matches end.
+    pub fn find_iter<'a>(&'a self, haystack: &'a [u8]) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let mut state = 0usize;
+        haystack.iter().enumerate().flat_map(move |(pos, &byte)| {
+            state = self.nodes[state].goto[byte as usize] as usize;
+            self.nodes[state]
+                .output
+                .iter()
+                .map(move |&pattern_idx| (pattern_idx, pos + 1 - self.pattern_lens[pattern_idx]))
+        })
+    }
+}
@@ -1,8 +1,14 @@
 use clap::Parser;
+use memmap2::Mmap;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::sync::LazyLock;
 
+mod aho_corasick;
+use aho_corasick::AhoCorasick;
+mod phash;
+use phash::{dhash, BkTree};
+
 trait SizeRange {
     fn max(&self) -> usize;
 }
@@ -20,80 +26,361 @@ enum Footer<'a> {
     None, // Take maximum size allowed by the range
     Inclusive(&'a [u8]), // Take until end of footer or maximum allowed by the range
     Exclusive(&'a [u8]), // Take until beginning of footer or maximum allowed by the range
+    // Locate the true (start, size) of a candidate by walking its own
+    // container structure rather than searching for a footer, given the
+    // whole buffer and the position the header pattern matched at (which
+    // may sit inside the true start, as with TAR's `ustar` magic). Falls
+    // back to the range maximum from the match position if the structure
+    // doesn't check out.
+    Structured(fn(&[u8], usize) -> Option<(usize, usize)>),
 }
 
 impl<'a> Footer<'a> {
     fn file_size_after_footer_pos(&self) -> usize {
         match self {
-            Footer::None | Footer::Exclusive(_) => 0,
+            Footer::None | Footer::Exclusive(_) | Footer::Structured(_) => 0,
             Footer::Inclusive(data) => data.len(),
         }
     }
 }
 
+/// How much a carved candidate has been checked before being reported,
+/// from weakest to strongest. Ordered so `--min-confidence` can simply
+/// compare against it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+enum DetectionScore {
+    /// Only the header bytes matched; nothing else was checked.
+    MagicOnly,
+    /// A matching footer was also found.
+    MagicPlusFooter,
+    /// The candidate's internal structure (a checksum, a plausible field,
+    /// a walked container) was checked and makes sense.
+    StructureValidated,
+}
+
+impl std::fmt::Display for DetectionScore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DetectionScore::MagicOnly => "magic-only",
+            DetectionScore::MagicPlusFooter => "magic+footer",
+            DetectionScore::StructureValidated => "structure-validated",
+        })
+    }
+}
+
+/// A basic CRC-32 (IEEE 802.3), used to validate a PNG IHDR chunk.
+fn crc32_ieee(data: &[u8]) -> u32 {
+    const POLY: u32 = 0xEDB88320;
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Validates that a PNG candidate's first chunk is a well-formed `IHDR`:
+/// right length, sane declared dimensions, and a matching CRC.
+fn validate_png(data: &[u8]) -> Option<DetectionScore> {
+    const SIG_LEN: usize = 8;
+    if data.len() < SIG_LEN + 8 + 13 + 4 {
+        return Some(DetectionScore::MagicOnly); // too little data to say more
+    }
+
+    let chunk_len = u32::from_be_bytes(data[SIG_LEN..SIG_LEN + 4].try_into().unwrap()) as usize;
+    let chunk_type = &data[SIG_LEN + 4..SIG_LEN + 8];
+    if chunk_len != 13 || chunk_type != b"IHDR" {
+        return None;
+    }
+
+    let ihdr = &data[SIG_LEN + 8..SIG_LEN + 8 + 13];
+    let width = u32::from_be_bytes(ihdr[0..4].try_into().unwrap());
+    let height = u32::from_be_bytes(ihdr[4..8].try_into().unwrap());
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let crc_body = &data[SIG_LEN + 4..SIG_LEN + 8 + 13]; // type + chunk data
+    let expected_crc = u32::from_be_bytes(data[SIG_LEN + 8 + 13..SIG_LEN + 8 + 13 + 4].try_into().unwrap());
+    if crc32_ieee(crc_body) != expected_crc {
+        return None;
+    }
+
+    Some(DetectionScore::StructureValidated)
+}
+
+/// Validates that the marker right after JPEG's `FFD8` SOI is itself a
+/// plausible `FFxx` segment with a length that fits the candidate.
+fn validate_jpg(data: &[u8]) -> Option<DetectionScore> {
+    if data.len() < 6 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+    if data[2] != 0xFF || data[3] == 0x00 || data[3] == 0xFF {
+        return None;
+    }
+
+    let segment_len = u16::from_be_bytes(data[4..6].try_into().unwrap()) as usize;
+    if segment_len < 2 || 4 + segment_len > data.len() {
+        return None;
+    }
+
+    Some(DetectionScore::StructureValidated)
+}
+
+/// Rejects a BMP candidate unless its little-endian file-size field (offset
+/// 2) names a plausible size: at least a minimal header, and not bigger
+/// than the candidate region it was carved into.
+fn validate_bmp(data: &[u8]) -> Option<DetectionScore> {
+    if data.len() < 6 {
+        return None;
+    }
+    let declared_size = u32::from_le_bytes(data[2..6].try_into().unwrap()) as usize;
+    if declared_size < 54 || declared_size > data.len() {
+        return None;
+    }
+
+    Some(DetectionScore::StructureValidated)
+}
+
+/// Walks ISO BMFF boxes from `buffer[0]`, returning the total span covered
+/// before an implausible size/type or an overrun of `buffer`.
+fn iso_bmff_box_span(buffer: &[u8]) -> Option<usize> {
+    let mut offset = 0usize;
+
+    loop {
+        if offset + 8 > buffer.len() {
+            break;
+        }
+        let box_type = &buffer[offset + 4..offset + 8];
+        if !box_type.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+            break;
+        }
+
+        let declared_size = u32::from_be_bytes(buffer[offset..offset + 4].try_into().unwrap()) as u64;
+        let box_size = match declared_size {
+            0 => (buffer.len() - offset) as u64, // runs to end of file
+            1 => {
+                if offset + 16 > buffer.len() {
+                    break;
+                }
+                u64::from_be_bytes(buffer[offset + 8..offset + 16].try_into().unwrap())
+            }
+            size if size < 8 => break, // smaller than a box header: implausible
+            size => size,
+        };
+
+        let Some(next_offset) = (offset as u64).checked_add(box_size) else {
+            break; // extended size overflowed: implausible, stop walking
+        };
+        if box_size == 0 || next_offset > buffer.len() as u64 {
+            break;
+        }
+        offset = next_offset as usize;
+
+        if declared_size == 0 {
+            break; // that box already ran to the end of the buffer
+        }
+    }
+
+    if offset == 0 { None } else { Some(offset) }
+}
+
+/// Reads the brand out of an `ftyp` box to tell the ISO BMFF family apart.
+fn iso_bmff_extension(buffer: &[u8]) -> &'static str {
+    match buffer.get(8..12).unwrap_or(&[]) {
+        b"qt  " => "mov",
+        b"M4A " | b"M4B " => "m4a",
+        b"heic" | b"heix" | b"heim" | b"heis" | b"mif1" | b"msf1" => "heic",
+        _ => "mp4",
+    }
+}
+
+/// Extensions the `mp4`-filed ISO BMFF signature might resolve to.
+const ISO_BMFF_FAMILY_EXTS: &[&str] = &["mp4", "mov", "m4a", "heic"];
+
+/// Extensions a candidate of signature `ext` might actually resolve to.
+fn candidate_exts(ext: &'static str) -> Vec<&'static str> {
+    if ext == "mp4" {
+        ISO_BMFF_FAMILY_EXTS.to_vec()
+    } else {
+        vec![ext]
+    }
+}
+
+const TAR_BLOCK_SIZE: usize = 512;
+/// Offset of the `ustar` magic within a tar header block.
+const TAR_MAGIC_OFFSET: usize = 257;
+
+/// Locates and sizes a TAR archive given the `ustar` magic's match position,
+/// walking headers from the surrounding 512-byte block until two
+/// consecutive all-zero blocks mark the end.
+fn tar_archive_span(slice: &[u8], magic_pos: usize) -> Option<(usize, usize)> {
+    let start = magic_pos.checked_sub(TAR_MAGIC_OFFSET)?;
+    let mut offset = start;
+    let mut consecutive_zero_blocks = 0u32;
+
+    loop {
+        let block = slice.get(offset..offset.checked_add(TAR_BLOCK_SIZE)?)?;
+
+        if block.iter().all(|&b| b == 0) {
+            consecutive_zero_blocks += 1;
+            offset += TAR_BLOCK_SIZE;
+            if consecutive_zero_blocks >= 2 {
+                return Some((start, offset - start));
+            }
+            continue;
+        }
+        consecutive_zero_blocks = 0;
+
+        let size_field = std::str::from_utf8(&block[124..136]).ok()?;
+        let content_size = usize::from_str_radix(
+            size_field.trim_matches(|c: char| c == '\0' || c.is_whitespace()),
+            8,
+        ).ok()?;
+        let content_blocks = content_size / TAR_BLOCK_SIZE
+            + if content_size % TAR_BLOCK_SIZE == 0 { 0 } else { 1 };
+
+        offset = offset
+            .checked_add(TAR_BLOCK_SIZE)?
+            .checked_add(content_blocks.checked_mul(TAR_BLOCK_SIZE)?)?;
+    }
+}
+
+struct Signature {
+    ext: &'static str,
+    size_range: Box<dyn SizeRange>,
+    header: &'static [u8],
+    footer: Footer<'static>,
+    /// Inspects the final candidate bytes and, when present, decides the
+    /// reported `DetectionScore` outright (or rejects the candidate by
+    /// returning `None`), overriding the score `carve_slice` derived from
+    /// the header/footer match alone.
+    validator: Option<fn(&[u8]) -> Option<DetectionScore>>,
+}
+
+fn sig(ext: &'static str, size_range: Box<dyn SizeRange>, header: &'static [u8], footer: Footer<'static>) -> Signature {
+    Signature { ext, size_range, header, footer, validator: None }
+}
+
+impl Signature {
+    fn with_validator(mut self, validator: fn(&[u8]) -> Option<DetectionScore>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+}
+
 // Define file signatures with extension, valid size range, header, and footer
-const FILE_SIGNATURES: LazyLock<Vec<(&str, Box<dyn SizeRange>, &[u8], Footer)>> = LazyLock::new(|| vec![
+const FILE_SIGNATURES: LazyLock<Vec<Signature>> = LazyLock::new(|| vec![
     // Archive/Binary files
-    ("zip", Box::new(..=10_000_000usize), b"PK\x03\x04", Footer::Inclusive(b"\x50\x4B\x05\x06")), // ZIP
-    ("rar", Box::new(..=10_000_000usize), b"Rar!", Footer::Inclusive(b"\x00\x00\x00\x00")), // RAR
-    ("7z", Box::new(..=10_000_000usize), b"7z\xBC\xAF\x27\x1C", Footer::Inclusive(b"\x00\x00\x00\x00")), // 7Z
-    ("tar", Box::new(..=10_000_000usize), b"ustar", Footer::Inclusive(b"\x00\x00\x00\x00")), // TAR
-    ("iso", Box::new(..=10_000_000usize), b"CD001", Footer::Inclusive(b"\x00\x00\x00\x00")), // ISO
+    sig("zip", Box::new(..=10_000_000usize), b"PK\x03\x04", Footer::Inclusive(b"\x50\x4B\x05\x06")), // ZIP
+    sig("rar", Box::new(..=10_000_000usize), b"Rar!", Footer::Inclusive(b"\x00\x00\x00\x00")), // RAR
+    sig("7z", Box::new(..=10_000_000usize), b"7z\xBC\xAF\x27\x1C", Footer::Inclusive(b"\x00\x00\x00\x00")), // 7Z
+    sig("tar", Box::new(..=10_000_000usize), b"ustar", Footer::Structured(tar_archive_span)), // TAR
+    sig("iso", Box::new(..=10_000_000usize), b"CD001", Footer::Inclusive(b"\x00\x00\x00\x00")), // ISO
 
     // Documents
-    ("doc", Box::new(..=10_000_000usize), b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1\x00\x00", Footer::Exclusive(b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1\x00\x00")), // DOC
-    ("doc", Box::new(..=10_000_000usize), b"\xd0\xcf\x11\xe0\xa1\xb1", Footer::None), // DOC
-    ("html", Box::new(..=10_000_000usize), b"<html", Footer::Inclusive(b"</html>")), // HTML
-    ("html", Box::new(..=10_000_000usize), b"<!DOCTYPE html", Footer::Inclusive(b"</html>")), // HTML
-    ("pdf", Box::new(..=10_000_000usize), b"%PDF-", Footer::Inclusive(b"%%EOF")), // PDF
-    ("rtf", Box::new(..=10_000_000usize), b"{\\rtf1", Footer::Inclusive(b"}")), // RTF
+    sig("doc", Box::new(..=10_000_000usize), b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1\x00\x00", Footer::Exclusive(b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1\x00\x00")), // DOC
+    sig("doc", Box::new(..=10_000_000usize), b"\xd0\xcf\x11\xe0\xa1\xb1", Footer::None), // DOC
+    sig("html", Box::new(..=10_000_000usize), b"<html", Footer::Inclusive(b"</html>")), // HTML
+    sig("html", Box::new(..=10_000_000usize), b"<!DOCTYPE html", Footer::Inclusive(b"</html>")), // HTML
+    sig("pdf", Box::new(..=10_000_000usize), b"%PDF-", Footer::Inclusive(b"%%EOF")), // PDF
+    sig("rtf", Box::new(..=10_000_000usize), b"{\\rtf1", Footer::Inclusive(b"}")), // RTF
     // TODO: search for words to guess a .txt?
 
     // Image files
-    // TODO: BMP could have less false positives if regexes were used?
-    ("bmp", Box::new(..=10_000_000usize), b"\x42\x4D", Footer::None), // BMP
-    ("gif", Box::new(..=5_000_000usize), b"\x47\x49\x46\x38\x37\x61", Footer::Inclusive(b"\x00\x3b")), // GIF
-    ("gif", Box::new(..=5_000_000usize), b"\x47\x49\x46\x38\x39\x61", Footer::Inclusive(b"\x00\x00\x3b")), // GIF
-    ("jpg", Box::new(..=200_000_000usize), b"\xff\xd8\xff\xe0\x00\x10", Footer::Inclusive(b"\xFF\xD9")), // JPEG
-    ("jpg", Box::new(..=200_000_000usize), b"\xff\xd8\xff\xe1", Footer::Inclusive(b"\xFF\xD9")), // JPEG
-    ("png", Box::new(..=10_000_000usize), b"\x89PNG\r\n\x1A\n", Footer::Inclusive(b"\xFF\xFC\xFD\xFE")), // PNG
-    ("tif", Box::new(..=10_000_000usize), b"\x49\x49\x2a\x00", Footer::None), // TIFF
-    ("tif", Box::new(..=10_000_000usize), b"\x4D\x4D\x00\x2A", Footer::None), // TIFF
+    sig("bmp", Box::new(..=10_000_000usize), b"\x42\x4D", Footer::None).with_validator(validate_bmp), // BMP
+    sig("gif", Box::new(..=5_000_000usize), b"\x47\x49\x46\x38\x37\x61", Footer::Inclusive(b"\x00\x3b")), // GIF
+    sig("gif", Box::new(..=5_000_000usize), b"\x47\x49\x46\x38\x39\x61", Footer::Inclusive(b"\x00\x00\x3b")), // GIF
+    sig("jpg", Box::new(..=200_000_000usize), b"\xff\xd8\xff\xe0\x00\x10", Footer::Inclusive(b"\xFF\xD9")).with_validator(validate_jpg), // JPEG
+    sig("jpg", Box::new(..=200_000_000usize), b"\xff\xd8\xff\xe1", Footer::Inclusive(b"\xFF\xD9")).with_validator(validate_jpg), // JPEG
+    sig("png", Box::new(..=10_000_000usize), b"\x89PNG\r\n\x1A\n", Footer::Inclusive(b"\xFF\xFC\xFD\xFE")).with_validator(validate_png), // PNG
+    sig("tif", Box::new(..=10_000_000usize), b"\x49\x49\x2a\x00", Footer::None), // TIFF
+    sig("tif", Box::new(..=10_000_000usize), b"\x4D\x4D\x00\x2A", Footer::None), // TIFF
 
     // Audio/Video
-    ("avi", Box::new(..=10_000_000usize), b"RIFF\x00\x00\x00AVI ", Footer::None), // AVI
-    ("mov", Box::new(..=10_000_000usize), b"\x00\x00\x00\x20ftyp", Footer::None), // MOV
-    ("mp3", Box::new(..=10_000_000usize), b"\x57\x41\x56\\45", Footer::Inclusive(b"\x00\x00\xFF")), // MP3
-    ("mp3", Box::new(..=10_000_000usize), b"\xFF\xFB\xD0\\", Footer::Inclusive(b"\xD1\x35\x51\xCC")), // MP3
-    ("mp3", Box::new(..=10_000_000usize), b"\x4C\x41\x4D\x45\\", Footer::None), // MP3
-    ("mp4", Box::new(..=10_000_000usize), b"\x00\x00\x00\x20ftyp", Footer::None), // MP4
-    ("wav", Box::new(..=10_000_000usize), b"RIFF\x00\x00\x00WAVE", Footer::None), // WAV
+    sig("avi", Box::new(..=10_000_000usize), b"RIFF\x00\x00\x00AVI ", Footer::None), // AVI
+    sig("mp3", Box::new(..=10_000_000usize), b"\x57\x41\x56\\45", Footer::Inclusive(b"\x00\x00\xFF")), // MP3
+    sig("mp3", Box::new(..=10_000_000usize), b"\xFF\xFB\xD0\\", Footer::Inclusive(b"\xD1\x35\x51\xCC")), // MP3
+    sig("mp3", Box::new(..=10_000_000usize), b"\x4C\x41\x4D\x45\\", Footer::None), // MP3
+    // Covers MP4, MOV, M4A and HEIF: they're all ISO BMFF containers sharing
+    // this `ftyp` header, distinguished by the brand read from inside it.
+    sig("mp4", Box::new(..=10_000_000usize), b"\x00\x00\x00\x20ftyp", Footer::Structured(|slice, pos| iso_bmff_box_span(&slice[pos..]).map(|span| (pos, span)))), // MP4/MOV/M4A/HEIF
+    sig("wav", Box::new(..=10_000_000usize), b"RIFF\x00\x00\x00WAVE", Footer::None), // WAV
 ]);
 
-fn carve_slice(slice: &[u8]) -> Vec<(usize, usize, usize)> {
-    let mut results = Vec::new();
-
-    for (idx, (_ext, size_range, header, footer)) in FILE_SIGNATURES.iter().enumerate() {
-        for pos in (0..slice.len() - header.len())
-            .filter(|ii| slice[(*ii)..*ii + header.len()] == **header)
-        {
-            // Check for footer in the remaining data
-            let file_size = match footer {
-                Footer::Inclusive(f) | Footer::Exclusive(f) => Some(f),
-                Footer::None => None,
+/// Builds an automaton over the header bytes of `active`, a subset of
+/// `FILE_SIGNATURES` indices; `carve_slice` must be given the same slice.
+fn build_header_automaton(active: &[usize]) -> AhoCorasick {
+    let headers: Vec<&[u8]> = active.iter().map(|&idx| FILE_SIGNATURES[idx].header).collect();
+    AhoCorasick::new(&headers)
+}
+
+fn carve_slice(
+    slice: &[u8],
+    active: &[usize],
+    automaton: &AhoCorasick,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+) -> Vec<(usize, usize, usize, DetectionScore)> {
+    automaton
+        .find_iter(slice)
+        .filter_map(|(local_idx, pos)| {
+            let idx = active[local_idx];
+            let signature = &FILE_SIGNATURES[idx];
+            let size_range = &signature.size_range;
+            let header = signature.header;
+            // `--max-size` overrides the per-signature range bound wherever
+            // there's no footer/structure to bound the candidate instead.
+            let effective_max = max_size.unwrap_or_else(|| size_range.max());
+
+            let (start, file_size, mut score) = if let Footer::Structured(locate) = &signature.footer {
+                match locate(slice, pos) {
+                    Some((start, size)) => (start, size.min(slice.len() - start), DetectionScore::StructureValidated),
+                    None => (pos, effective_max.min(slice.len() - pos), DetectionScore::MagicOnly),
+                }
+            } else {
+                // Check for footer in the remaining data
+                match &signature.footer {
+                    Footer::Inclusive(f) | Footer::Exclusive(f) => Some(f),
+                    Footer::None | Footer::Structured(_) => None,
+                }
+                    .and_then(|f| {
+                        // Bound the footer search to the plausible candidate
+                        // length instead of scanning to the end of a
+                        // (possibly multi-GB) buffer on every spurious header hit.
+                        let search_end = (pos + header.len()).saturating_add(effective_max).min(slice.len());
+                        find_static_signature(&slice[(pos+header.len())..search_end], f)
+                    })
+                    .map(|footer_pos| (
+                        pos,
+                        header.len() + footer_pos + signature.footer.file_size_after_footer_pos(),
+                        DetectionScore::MagicPlusFooter,
+                    ))
+                    .unwrap_or((pos, effective_max.min(slice.len() - pos), DetectionScore::MagicOnly))
+            };
+
+            // `--max-size`/`--min-size` override the per-signature bounds on
+            // every path, not just the no-footer-found fallback.
+            let file_size = max_size.map_or(file_size, |max| file_size.min(max));
+            if file_size < min_size.unwrap_or(0) {
+                return None;
             }
-                .and_then(|f| find_static_signature(&slice[(pos+header.len())..slice.len()], f))
-                .map(|pos| header.len() + pos + footer.file_size_after_footer_pos())
-                .unwrap_or(size_range.max().min(slice.len()));
 
-            results.push((idx, pos, file_size));
-        }
-    }
+            if let Some(validator) = signature.validator {
+                score = validator(&slice[start..start+file_size])?;
+            }
 
-    results
+            Some((idx, start, file_size, score))
+        })
+        .collect()
 }
 fn find_static_signature(buffer: &[u8], signature: &[u8]) -> Option<usize> {
-    for ii in 0..buffer.len() - signature.len() {
+    if signature.len() > buffer.len() {
+        return None;
+    }
+    for ii in 0..=buffer.len() - signature.len() {
         if &buffer[ii..ii + signature.len()] == signature {
             return Some(ii);
         }
@@ -101,34 +388,220 @@ fn find_static_signature(buffer: &[u8], signature: &[u8]) -> Option<usize> {
     None
 }
 
+/// Forensic-workflow knobs layered on top of plain header/footer carving.
+struct CarveOptions {
+    min_confidence: DetectionScore,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    types: Option<Vec<String>>,
+    exclude_types: Option<Vec<String>>,
+    report: Option<String>,
+    image_similarity: Option<u32>,
+}
+
+/// Indices into `FILE_SIGNATURES` worth scanning given `--types`/
+/// `--exclude-types`. Permissive for the `mp4`-filed ISO BMFF family, since
+/// which member it is isn't known until the `ftyp` brand is read;
+/// `ext_allowed` does the precise check once that's resolved.
+fn active_signature_indices(options: &CarveOptions) -> Vec<usize> {
+    (0..FILE_SIGNATURES.len())
+        .filter(|&idx| {
+            let exts = candidate_exts(FILE_SIGNATURES[idx].ext);
+            let included = options.types.as_ref().map(|types| exts.iter().any(|e| types.iter().any(|t| t == e))).unwrap_or(true);
+            let excluded = options.exclude_types.as_ref().map(|types| exts.iter().all(|e| types.iter().any(|t| t == e))).unwrap_or(false);
+            included && !excluded
+        })
+        .collect()
+}
+
+/// Whether a candidate's resolved extension (e.g. `mov`, not the ISO BMFF
+/// signature's static `mp4`) passes `--types`/`--exclude-types`.
+fn ext_allowed(ext: &str, options: &CarveOptions) -> bool {
+    let included = options.types.as_ref().map(|types| types.iter().any(|t| t == ext)).unwrap_or(true);
+    let excluded = options.exclude_types.as_ref().map(|types| types.iter().any(|t| t == ext)).unwrap_or(false);
+    included && !excluded
+}
+
+#[derive(serde::Serialize)]
+struct RecoveryRecord {
+    signature: String,
+    offset: usize,
+    length: usize,
+    output_path: String,
+    confidence: String,
+    duplicate: bool,
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes the recovery manifest as CSV if `report_path` ends in `.csv`,
+/// JSON otherwise.
+fn write_report(report_path: &str, records: &[RecoveryRecord]) -> io::Result<()> {
+    if report_path.ends_with(".csv") {
+        let mut csv = String::from("signature,offset,length,output_path,confidence,duplicate\n");
+        for record in records {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                csv_field(&record.signature),
+                record.offset,
+                record.length,
+                csv_field(&record.output_path),
+                csv_field(&record.confidence),
+                record.duplicate,
+            ));
+        }
+        std::fs::write(report_path, csv)
+    } else {
+        let json = serde_json::to_vec_pretty(records).map_err(io::Error::other)?;
+        std::fs::write(report_path, json)
+    }
+}
+
 /// NOTE: This function does not read FAT or similar sources of information.
 /// That means that files may be undiscovered by the carving algorithm even if they are not actually deleted.
-fn carve_file(input_file: &str, output_directory: &str) -> io::Result<()> {
+fn carve_file(input_file: &str, output_directory: &str, options: &CarveOptions) -> io::Result<()> {
     // Create output directory
     std::fs::create_dir_all(output_directory)?;
 
-    // Read the entire input file into memory
-    // TODO: would memory mapping be beneficial here?
-    let mut file = File::open(input_file)?;
-    let mut file_data = Vec::new();
-    file.read_to_end(&mut file_data)?;
+    // Memory-map the input rather than `read_to_end`-ing it into a `Vec`: the
+    // whole image is still addressed as one contiguous slice, but pages are
+    // faulted in by the OS on demand, so an image bigger than physical RAM no
+    // longer has to fit in memory up front. Because the slice is never split
+    // into windows, a header near the start and its footer a gigabyte later
+    // are just two offsets into the same buffer.
+    let file = File::open(input_file)?;
+    // SAFETY: we only read through this mapping, and nothing else in this
+    // process writes to `input_file` while it's mapped.
+    let mmap = unsafe { Mmap::map(&file)? };
+    let file_data: &[u8] = &mmap;
+
+    let active = active_signature_indices(options);
+    let automaton = build_header_automaton(&active);
+    let mut report_records = Vec::new();
+    let mut image_candidates = Vec::new();
+
+    // Check for each signature in the mapped file data
+    for (file_type_index, start, size, score) in carve_slice(file_data, &active, &automaton, options.min_size, options.max_size) {
+        if score < options.min_confidence {
+            continue;
+        }
 
-    // Check for each signature in the loaded file data
-    for (file_type_index, start, size) in carve_slice(&file_data) {
         let file_type = &(*FILE_SIGNATURES)[file_type_index];
-        println!("Found {} signature at offset {} (size {} B)", file_type.0, start, size);
+        let ext = if file_type.ext == "mp4" {
+            iso_bmff_extension(&file_data[start..start+size])
+        } else {
+            file_type.ext
+        };
+        if !ext_allowed(ext, options) {
+            continue;
+        }
+        println!("Found {} signature at offset {} (size {} B, confidence {})", ext, start, size, score);
 
-        let file_name = format!("{}/recovered_{}_{}.{}", output_directory, start, file_type_index, file_type.0);
+        let file_name = format!("{}/recovered_{}_{}.{}", output_directory, start, file_type_index, ext);
         let mut output_file = OpenOptions::new()
             .write(true)
             .create(true)
-            .open(file_name)?;
+            .open(&file_name)?;
         output_file.write_all(&file_data[start..start+size])?;
+
+        if options.image_similarity.is_some() && (ext == "jpg" || ext == "png") {
+            image_candidates.push((file_name.clone(), size));
+        }
+
+        if options.report.is_some() {
+            report_records.push(RecoveryRecord {
+                signature: ext.to_string(),
+                offset: start,
+                length: size,
+                output_path: file_name,
+                confidence: score.to_string(),
+                duplicate: false,
+            });
+        }
+    }
+
+    if let Some(threshold) = options.image_similarity {
+        let moved = dedup_images(output_directory, &image_candidates, threshold)?;
+        for record in &mut report_records {
+            if let Some(final_path) = moved.get(&record.output_path) {
+                record.output_path = final_path.clone();
+                record.duplicate = true;
+            }
+        }
+    }
+
+    if let Some(report_path) = &options.report {
+        write_report(report_path, &report_records)?;
     }
 
     Ok(())
 }
 
+/// Groups carved images within `threshold` Hamming distance and keeps only
+/// the largest of each group, moving the rest into `duplicates`. Returns
+/// each moved file's original path mapped to its final location.
+fn dedup_images(output_directory: &str, candidates: &[(String, usize)], threshold: u32) -> io::Result<std::collections::HashMap<String, String>> {
+    let mut moved = std::collections::HashMap::new();
+    if candidates.is_empty() {
+        return Ok(moved);
+    }
+
+    let duplicates_dir = format!("{}/duplicates", output_directory);
+    std::fs::create_dir_all(&duplicates_dir)?;
+
+    let mut tree = BkTree::new();
+    let mut representatives: Vec<(String, usize)> = Vec::new();
+
+    for (path, size) in candidates {
+        let Ok(img) = image::open(path) else {
+            continue;
+        };
+        let hash = dhash(&img);
+
+        match tree.find_within(hash, threshold).first() {
+            Some(&rep_idx) => {
+                let (rep_path, rep_size) = &mut representatives[rep_idx];
+                if size > rep_size {
+                    // The new candidate is more complete; demote the old representative.
+                    let final_path = move_to_duplicates(rep_path, &duplicates_dir)?;
+                    moved.insert(rep_path.clone(), final_path);
+                    *rep_path = path.clone();
+                    *rep_size = *size;
+                    // Re-index under the new representative's hash too, so a
+                    // later candidate close to it (but not to the original
+                    // seed) still routes into this cluster.
+                    tree.insert(hash, rep_idx);
+                } else {
+                    let final_path = move_to_duplicates(path, &duplicates_dir)?;
+                    moved.insert(path.clone(), final_path);
+                }
+            }
+            None => {
+                let idx = representatives.len();
+                representatives.push((path.clone(), *size));
+                tree.insert(hash, idx);
+            }
+        }
+    }
+
+    Ok(moved)
+}
+
+fn move_to_duplicates(path: &str, duplicates_dir: &str) -> io::Result<String> {
+    let file_name = std::path::Path::new(path)
+        .file_name()
+        .expect("carved output paths always have a file name");
+    let dest = std::path::Path::new(duplicates_dir).join(file_name);
+    std::fs::rename(path, &dest)?;
+    Ok(dest.to_string_lossy().into_owned())
+}
+
 #[derive(Parser)]
 struct CliArgs {
     /// Input .img/.dd/.raw file
@@ -142,6 +615,34 @@ struct CliArgs {
     /// Directory to save recovered files to
     #[arg(long)]
     output_directory: String,
+
+    /// Suppress carved candidates scored below this confidence
+    #[arg(long, value_enum, default_value_t = DetectionScore::MagicOnly)]
+    min_confidence: DetectionScore,
+
+    /// Override the per-signature minimum carved size, in bytes
+    #[arg(long)]
+    min_size: Option<usize>,
+
+    /// Override the per-signature maximum carved size, in bytes
+    #[arg(long)]
+    max_size: Option<usize>,
+
+    /// Restrict carving to these file extensions (comma-separated, e.g. "jpg,pdf,zip")
+    #[arg(long, value_delimiter = ',')]
+    types: Option<Vec<String>>,
+
+    /// Exclude these file extensions from carving (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    exclude_types: Option<Vec<String>>,
+
+    /// Write a machine-readable recovery manifest (JSON, or CSV if the path ends in ".csv")
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Deduplicate carved images whose perceptual hash is within this Hamming distance of each other
+    #[arg(long)]
+    image_similarity: Option<u32>,
 }
 
 fn main() -> io::Result<()> {
@@ -152,11 +653,122 @@ fn main() -> io::Result<()> {
         std::process::exit(1);
     }
 
+    let options = CarveOptions {
+        min_confidence: cli_args.min_confidence,
+        min_size: cli_args.min_size,
+        max_size: cli_args.max_size,
+        types: cli_args.types,
+        exclude_types: cli_args.exclude_types,
+        report: cli_args.report,
+        image_similarity: cli_args.image_similarity,
+    };
+
     if let Some(input_file) = cli_args.input_file {
-        carve_file(&input_file, &cli_args.output_directory)?;
+        carve_file(&input_file, &cli_args.output_directory, &options)?;
     } else if let Some(_input_location) = cli_args.input_location {
         // TODO: carve a location
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_static_signature_matches_at_the_final_offset() {
+        let buffer = b"xxx}";
+        assert_eq!(find_static_signature(buffer, b"}"), Some(3));
+    }
+
+    #[test]
+    fn iso_bmff_box_span_stops_instead_of_overflowing_on_a_bogus_extended_size() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&16u32.to_be_bytes()); // box1: plausible 16-byte box
+        buf.extend_from_slice(b"free");
+        buf.extend_from_slice(&[0u8; 8]);
+        buf.extend_from_slice(&1u32.to_be_bytes()); // box2: declared_size == 1, extended size follows
+        buf.extend_from_slice(b"ftyp");
+        buf.extend_from_slice(&(u64::MAX - 5).to_be_bytes()); // would overflow offset + box_size
+        assert_eq!(iso_bmff_box_span(&buf), Some(16));
+    }
+
+    #[test]
+    fn iso_bmff_box_span_sums_a_couple_of_well_formed_boxes() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&16u32.to_be_bytes()); // box1: 16 bytes total
+        buf.extend_from_slice(b"ftyp");
+        buf.extend_from_slice(b"isom");
+        buf.extend_from_slice(&[0u8; 4]); // pad box1 out to its declared size
+        buf.extend_from_slice(&8u32.to_be_bytes()); // box2: 8 bytes total (header only)
+        buf.extend_from_slice(b"free");
+        assert_eq!(iso_bmff_box_span(&buf), Some(24));
+    }
+
+    #[test]
+    fn validate_png_scores_a_truncated_candidate_as_magic_only_not_magic_plus_footer() {
+        let buf = b"\x89PNG\r\n\x1A\n";
+        assert_eq!(validate_png(buf), Some(DetectionScore::MagicOnly));
+    }
+
+    #[test]
+    fn validate_png_rejects_a_zero_dimension() {
+        let mut buf = b"\x89PNG\r\n\x1A\n".to_vec();
+        buf.extend_from_slice(&13u32.to_be_bytes());
+        buf.extend_from_slice(b"IHDR");
+        let ihdr = [0u8; 13]; // width == 0
+        buf.extend_from_slice(&ihdr);
+        let crc = crc32_ieee(&[b"IHDR".as_slice(), &ihdr].concat());
+        buf.extend_from_slice(&crc.to_be_bytes());
+        assert_eq!(validate_png(&buf), None);
+    }
+
+    #[test]
+    fn validate_jpg_rejects_ff00_in_place_of_a_marker() {
+        let buf = [0xFFu8, 0xD8, 0xFF, 0x00, 0x00, 0x10];
+        assert_eq!(validate_jpg(&buf), None);
+    }
+
+    #[test]
+    fn validate_jpg_accepts_a_plausible_segment() {
+        let mut buf = vec![0xFFu8, 0xD8, 0xFF, 0xE0, 0x00, 16];
+        buf.extend_from_slice(&[0u8; 14]);
+        assert_eq!(validate_jpg(&buf), Some(DetectionScore::StructureValidated));
+    }
+
+    #[test]
+    fn validate_bmp_rejects_a_size_field_bigger_than_the_candidate() {
+        let mut buf = vec![0u8; 20];
+        buf[2..6].copy_from_slice(&200u32.to_le_bytes());
+        assert_eq!(validate_bmp(&buf), None);
+    }
+
+    #[test]
+    fn validate_bmp_accepts_a_plausible_size_field() {
+        let mut buf = vec![0u8; 60];
+        buf[2..6].copy_from_slice(&54u32.to_le_bytes());
+        assert_eq!(validate_bmp(&buf), Some(DetectionScore::StructureValidated));
+    }
+
+    fn tar_header(content_len: usize) -> Vec<u8> {
+        let mut block = vec![0u8; TAR_BLOCK_SIZE];
+        block[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5].copy_from_slice(b"ustar");
+        let size_field = format!("{:011o}\0", content_len);
+        block[124..136].copy_from_slice(size_field.as_bytes());
+        block
+    }
+
+    #[test]
+    fn tar_archive_span_covers_the_header_content_and_terminating_blocks() {
+        let content = b"hello";
+        let mut buf = tar_header(content.len());
+        buf.extend_from_slice(content);
+        let padded_len = (buf.len() + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE;
+        buf.resize(padded_len, 0);
+        buf.extend_from_slice(&[0u8; TAR_BLOCK_SIZE * 2]); // two terminating zero blocks
+
+        let total_len = buf.len();
+        assert_eq!(tar_archive_span(&buf, TAR_MAGIC_OFFSET), Some((0, total_len)));
+    }
+}
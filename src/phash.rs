@@ -0,0 +1,123 @@
+//! Perceptual image hashing and a BK-tree for approximate duplicate lookup.
+//!
+//! A dHash fingerprint is tolerant to re-encoding, thumbnailing, and minor
+//! truncation, so carved copies of the same logical image hash close to each
+//! other even when their bytes differ completely. The BK-tree indexes those
+//! fingerprints by Hamming distance so a "is anything within threshold T of
+//! this hash" query doesn't require comparing against every prior image.
+
+/// Computes a 64-bit difference hash: downscale to 9x8 grayscale, then set
+/// bit `(x, y)` whenever pixel `(x, y)` is brighter than its right neighbor.
+pub fn dhash(img: &image::DynamicImage) -> u64 {
+    let small = img
+        .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+        .to_luma8();
+
+    let mut hash = 0u64;
+    let mut bit = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            if left > right {
+                hash |= 1 << bit;
+            }
+            bit += 1;
+        }
+    }
+    hash
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+struct Node {
+    hash: u64,
+    payload: usize,
+    /// Children indexed by their Hamming distance from this node.
+    children: Vec<(u32, usize)>,
+}
+
+/// A BK-tree over `u64` Hamming-distance fingerprints, mapping each inserted
+/// hash to a caller-supplied `payload` (typically an index into a side
+/// table of carve metadata).
+pub struct BkTree {
+    root: Option<usize>,
+    nodes: Vec<Node>,
+}
+
+impl BkTree {
+    pub fn new() -> Self {
+        BkTree { root: None, nodes: Vec::new() }
+    }
+
+    pub fn insert(&mut self, hash: u64, payload: usize) {
+        let new_idx = self.nodes.len();
+        self.nodes.push(Node { hash, payload, children: Vec::new() });
+
+        let Some(root) = self.root else {
+            self.root = Some(new_idx);
+            return;
+        };
+
+        let mut cur = root;
+        loop {
+            let dist = hamming_distance(self.nodes[cur].hash, hash);
+            match self.nodes[cur].children.iter().find(|&&(d, _)| d == dist) {
+                Some(&(_, child)) => cur = child,
+                None => {
+                    self.nodes[cur].children.push((dist, new_idx));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns the payloads of every inserted hash within `threshold` of
+    /// `hash`, pruning subtrees the triangle inequality rules out.
+    pub fn find_within(&self, hash: u64, threshold: u32) -> Vec<usize> {
+        let mut results = Vec::new();
+        if let Some(root) = self.root {
+            self.search(root, hash, threshold, &mut results);
+        }
+        results
+    }
+
+    fn search(&self, node_idx: usize, hash: u64, threshold: u32, results: &mut Vec<usize>) {
+        let node = &self.nodes[node_idx];
+        let dist = hamming_distance(node.hash, hash);
+        if dist <= threshold {
+            results.push(node.payload);
+        }
+
+        let lo = dist.saturating_sub(threshold);
+        let hi = dist + threshold;
+        for &(child_dist, child_idx) in &node.children {
+            if child_dist >= lo && child_dist <= hi {
+                self.search(child_idx, hash, threshold, results);
+            }
+        }
+    }
+}
+
+impl Default for BkTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_within_follows_a_promoted_representative() {
+        let mut tree = BkTree::new();
+        tree.insert(0b000, 0); // A seeds the cluster
+        tree.insert(0b001, 0); // B displaces A, re-indexed under the same payload
+
+        // C is within 1 bit of B but 2 bits from A: only reachable through B's node.
+        assert_eq!(tree.find_within(0b011, 1), vec![0]);
+    }
+}